@@ -7,9 +7,8 @@
 mod uffd_utils;
 
 use std::fs::File;
-use std::os::unix::net::UnixListener;
 
-use uffd_utils::{Runtime, UffdHandler};
+use uffd_utils::{Runtime, UdsListener, UffdHandler};
 
 fn main() {
     let mut args = std::env::args();
@@ -19,16 +18,20 @@ fn main() {
     let file = File::open(mem_file_path).expect("Cannot open memfile");
 
     // Get Uffd from UDS. We'll use the uffd to handle PFs for Firecracker.
-    let listener = UnixListener::bind(uffd_sock_path).expect("Cannot bind to socket path");
-    let (stream, _) = listener.accept().expect("Cannot listen on UDS socket");
+    let listener = UdsListener::bind(uffd_sock_path);
+    listener.exit_on_signals(|| ());
+    let stream = listener.accept();
 
     let mut runtime = Runtime::new(stream, file);
-    runtime.run(|uffd_handler: &mut UffdHandler| {
+    runtime.run(|uffd_handler: &UffdHandler| {
         // Read an event from the userfaultfd.
-        let event = uffd_handler
-            .read_event()
-            .expect("Failed to read uffd_msg")
-            .expect("uffd_msg not ready");
+        let event = match uffd_handler.read_event() {
+            Ok(event) => event.expect("uffd_msg not ready"),
+            Err(err) => {
+                uffd_handler.handle_read_error(err);
+                return;
+            }
+        };
 
         if let userfaultfd::Event::Pagefault { .. } = event {
             panic!("Fear me! I am the malicious page fault handler.")
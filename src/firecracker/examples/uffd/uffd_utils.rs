@@ -4,14 +4,23 @@
 // Not everything is used by both binaries
 #![allow(dead_code)]
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
-use std::os::unix::net::UnixStream;
-use std::ptr;
+use std::io::{ErrorKind, Read, Write};
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+use std::{ptr, thread};
 
 use serde::{Deserialize, Serialize};
 use userfaultfd::{Error, Event, Uffd};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use vmm_sys_util::signal::register_signal_handler;
 use vmm_sys_util::sock_ctrl_msg::ScmSocket;
 
 // This is the same with the one used in src/vmm.
@@ -26,6 +35,8 @@ pub struct GuestRegionUffdMapping {
     /// Base host virtual address where the guest memory contents for this region
     /// should be copied/populated.
     pub base_host_virt_addr: u64,
+    /// Base guest physical address of this region.
+    pub base_guest_phys_addr: u64,
     /// Region size.
     pub size: usize,
     /// Offset in the backend file/buffer where the region contents are.
@@ -42,10 +53,68 @@ pub enum MemPageState {
     Anonymous,
 }
 
-#[derive(Debug, Clone)]
+impl MemPageState {
+    /// Whether a page in this state is backed by memory in the guest.
+    pub fn is_resident(&self) -> bool {
+        matches!(self, MemPageState::FromFile | MemPageState::Anonymous)
+    }
+}
+
+/// Upper bounds, in microseconds, of the page fault latency histogram buckets.
+/// Faults slower than the last bound are counted in an extra bucket.
+const LATENCY_BUCKETS_US: [u64; 4] = [10, 100, 1_000, 10_000];
+const LATENCY_BUCKET_LABELS: [&str; LATENCY_BUCKETS_US.len() + 1] =
+    ["<10us", "<100us", "<1ms", "<10ms", ">=10ms"];
+
+/// Coarse histogram of the time taken to serve page faults.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicUsize; LATENCY_BUCKETS_US.len() + 1],
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, latency: Duration) {
+        let latency_us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|bound| latency_us < *bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of faults recorded in each bucket.
+    pub fn counts(&self) -> Vec<usize> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
 pub struct MemRegion {
     pub mapping: GuestRegionUffdMapping,
-    page_states: HashMap<u64, MemPageState>,
+    // Page faults may be served from several threads at once, see `Runtime::run_parallel`.
+    page_states: Mutex<HashMap<u64, MemPageState>>,
+    pub fault_latency: LatencyHistogram,
+}
+
+impl MemRegion {
+    /// Checks whether the given host virtual address falls within this region.
+    pub fn contains(&self, addr: u64) -> bool {
+        let start = self.mapping.base_host_virt_addr;
+        start <= addr && addr < start + self.mapping.size as u64
+    }
+}
+
+/// Memory file providing the contents of a guest physical address range on top of
+/// the base memory file, e.g. a diff snapshot layered over a full one.
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    /// Guest physical addresses this overlay provides the contents of, starting with
+    /// the beginning of the file.
+    pub guest_range: Range<u64>,
+    buffer: *const u8,
 }
 
 #[derive(Debug)]
@@ -53,11 +122,40 @@ pub struct UffdHandler {
     pub mem_regions: Vec<MemRegion>,
     pub page_size: usize,
     backing_buffer: *const u8,
+    // Checked in order, before falling back to `backing_buffer`.
+    overlays: Vec<Overlay>,
     uffd: Uffd,
+    total_pages: usize,
+    resident_pages: AtomicUsize,
+    fully_populated_reported: AtomicBool,
+    unhandled_events: Mutex<BTreeMap<&'static str, usize>>,
+    // Keeps events read by different workers in order, see `read_event_ordered`.
+    event_read: Mutex<()>,
+    event_order: RwLock<()>,
+}
+
+/// Held while handling an event returned by `UffdHandler::read_event_ordered`.
+enum EventGuard<'a> {
+    Pagefault(RwLockReadGuard<'a, ()>),
+    Other(RwLockWriteGuard<'a, ()>),
 }
 
+// # Safety:
+// `backing_buffer` and the overlay buffers point to read-only mappings of the memory
+// files owned by the `Runtime`, which outlives its handlers. Page states are protected by a mutex
+// and the userfaultfd ioctls can be issued concurrently from multiple threads.
+unsafe impl Send for UffdHandler {}
+// # Safety:
+// See above.
+unsafe impl Sync for UffdHandler {}
+
 impl UffdHandler {
-    pub fn from_unix_stream(stream: &UnixStream, backing_buffer: *const u8, size: usize) -> Self {
+    pub fn from_unix_stream(
+        stream: &UnixStream,
+        backing_buffer: *const u8,
+        size: usize,
+        overlays: Vec<Overlay>,
+    ) -> Self {
         let mut message_buf = vec![0u8; 1024];
         let (bytes_read, file) = stream
             .recv_with_fd(&mut message_buf[..])
@@ -75,145 +173,688 @@ impl UffdHandler {
 
         // Make sure memory size matches backing data size.
         assert_eq!(memsize, size);
-        assert!(page_size.is_power_of_two());
+        validate_page_size(page_size);
+        for mapping in mappings.iter() {
+            assert_eq!(
+                mapping.base_host_virt_addr % page_size as u64,
+                0,
+                "Region at {:#x} is not aligned to the page size {page_size}",
+                mapping.base_host_virt_addr
+            );
+        }
+        for overlay in overlays.iter() {
+            assert!(
+                overlay.guest_range.start % page_size as u64 == 0
+                    && overlay.guest_range.end % page_size as u64 == 0,
+                "Overlay {:#x?} is not aligned to the page size {page_size}",
+                overlay.guest_range
+            );
+        }
 
         let uffd = unsafe { Uffd::from_raw_fd(file.into_raw_fd()) };
 
         let mem_regions = create_mem_regions(&mappings, page_size);
+        let total_pages = mem_regions
+            .iter()
+            .map(|region| region.page_states.lock().unwrap().len())
+            .sum();
 
         Self {
             mem_regions,
             page_size,
             backing_buffer,
+            overlays,
             uffd,
+            total_pages,
+            resident_pages: AtomicUsize::new(0),
+            fully_populated_reported: AtomicBool::new(false),
+            unhandled_events: Mutex::default(),
+            event_read: Mutex::default(),
+            event_order: RwLock::default(),
         }
     }
 
-    pub fn read_event(&mut self) -> Result<Option<Event>, Error> {
+    pub fn read_event(&self) -> Result<Option<Event>, Error> {
         self.uffd.read_event()
     }
 
-    pub fn update_mem_state_mappings(&mut self, start: u64, end: u64, state: MemPageState) {
-        for region in self.mem_regions.iter_mut() {
-            for (key, value) in region.page_states.iter_mut() {
-                if key >= &start && key < &end {
+    /// Same as [`UffdHandler::read_event`], for threads handling events of the same uffd
+    /// concurrently. The event must be handled while holding the returned guard.
+    ///
+    /// Page faults can be served in any order, but other events change the state of the
+    /// pages later faults rely on: e.g. a page removed by the balloon must be zeroed when
+    /// faulted in again, not populated from the memory file. So such an event is only
+    /// handled once the faults read before it are served, and faults read after it wait
+    /// for it to be handled.
+    fn read_event_ordered(&self) -> Result<Option<(Event, EventGuard<'_>)>, Error> {
+        // Taking the guard while still holding `event_read` makes the order in which
+        // guards are granted match the order in which events were read.
+        let _reading = self.event_read.lock().unwrap();
+        let Some(event) = self.uffd.read_event()? else {
+            return Ok(None);
+        };
+        let guard = match event {
+            Event::Pagefault { .. } => EventGuard::Pagefault(self.event_order.read().unwrap()),
+            _ => EventGuard::Other(self.event_order.write().unwrap()),
+        };
+        Ok(Some((event, guard)))
+    }
+
+    /// Sets the state of the pages starting in `start..end`. Only the pages in the range are
+    /// visited, so the cost of a fault doesn't grow with the size of the guest memory.
+    pub fn update_mem_state_mappings(&self, start: u64, end: u64, state: MemPageState) {
+        let page_size = self.page_size as u64;
+        for region in self.mem_regions.iter() {
+            let region_start = region.mapping.base_host_virt_addr;
+            let region_end = region_start + region.mapping.size as u64;
+            if end <= region_start || region_end <= start {
+                continue;
+            }
+
+            // Page states are keyed by the address of the page, counted from the region start.
+            let mut addr =
+                region_start + (start.max(region_start) - region_start).next_multiple_of(page_size);
+            let mut page_states = region.page_states.lock().unwrap();
+            while addr < end.min(region_end) {
+                if let Some(value) = page_states.get_mut(&addr) {
+                    match (value.is_resident(), state.is_resident()) {
+                        (false, true) => {
+                            self.resident_pages.fetch_add(1, Ordering::SeqCst);
+                        }
+                        (true, false) => {
+                            self.resident_pages.fetch_sub(1, Ordering::SeqCst);
+                        }
+                        _ => {}
+                    }
                     *value = state;
                 }
+                addr += page_size;
             }
         }
     }
 
-    pub fn serve_pf(&mut self, addr: *mut u8, len: usize) {
+    /// Number of pages in all the guest memory regions.
+    pub fn total_pages(&self) -> usize {
+        self.total_pages
+    }
+
+    /// Number of pages currently populated, either from the memory file or with zeroes.
+    /// Pages removed by the balloon device don't count until they're faulted in again.
+    pub fn resident_pages(&self) -> usize {
+        self.resident_pages.load(Ordering::SeqCst)
+    }
+
+    /// Returns `true` exactly once, the first time it's called after the whole guest
+    /// memory has been populated. Handlers can use it to signal that the restored guest
+    /// memory is warm.
+    pub fn take_fully_populated(&self) -> bool {
+        self.resident_pages() == self.total_pages
+            && !self.fully_populated_reported.swap(true, Ordering::SeqCst)
+    }
+
+    /// Returns the memory region that contains the given host virtual address.
+    ///
+    /// Regions are not required to be contiguous, neither in the host address
+    /// space nor in the backing file, so the lookup goes through the region
+    /// layout received from Firecracker.
+    pub fn region_for(&self, addr: u64) -> Option<&MemRegion> {
+        self.mem_regions.iter().find(|region| region.contains(addr))
+    }
+
+    /// Returns the offset in the memory file where the contents backing the host
+    /// virtual address `addr` live, or `None` if the address is not part of any
+    /// guest memory region (e.g. it falls in a gap between two regions).
+    ///
+    /// This only covers the base memory file: for an address served from an
+    /// overlay (see [`Runtime::with_overlays`]), the returned offset is the one
+    /// its contents would have in the base memory file.
+    pub fn addr_to_offset(&self, addr: u64) -> Option<u64> {
+        self.region_for(addr)
+            .map(|region| region.mapping.offset + (addr - region.mapping.base_host_virt_addr))
+    }
+
+    /// Serves a page fault at `addr` and returns the state the served pages
+    /// ended up in.
+    pub fn serve_pf(&self, addr: *mut u8, len: usize) -> MemPageState {
+        let start_time = Instant::now();
         // Find the start of the page that the current faulting address belongs to.
         let dst = (addr as usize & !(self.page_size - 1)) as *mut libc::c_void;
         let fault_page_addr = dst as u64;
 
+        let Some(region) = self.region_for(fault_page_addr) else {
+            panic!(
+                "Could not find addr: {:?} within guest region mappings.",
+                addr
+            );
+        };
+
         // Get the state of the current faulting page.
+        let state = region
+            .page_states
+            .lock()
+            .unwrap()
+            .get(&fault_page_addr)
+            .copied();
+        let new_state = match state {
+            // Our simple PF handler has a simple strategy:
+            // There exist 4 states in which a memory page can be in:
+            // 1. Uninitialized - page was never touched
+            // 2. FromFile - the page is populated with content from snapshotted memory file
+            // 3. Removed - MADV_DONTNEED was called due to balloon inflation
+            // 4. Anonymous - page was zeroed out -> this implies that more than one page fault
+            //    event was received. This can be a consequence of guest reclaiming back its memory
+            //    from the host (through balloon device)
+            Some(MemPageState::Uninitialized) | Some(MemPageState::FromFile) => {
+                let (start, end) = self.populate_from_file(fault_page_addr, len);
+                self.update_mem_state_mappings(start, end, MemPageState::FromFile);
+                MemPageState::FromFile
+            }
+            Some(MemPageState::Removed) | Some(MemPageState::Anonymous) => {
+                let (start, end) = self.zero_out(fault_page_addr);
+                self.update_mem_state_mappings(start, end, MemPageState::Anonymous);
+                MemPageState::Anonymous
+            }
+            None => panic!(
+                "Could not find page state for addr: {:?} in its region.",
+                addr
+            ),
+        };
+
+        region.fault_latency.record(start_time.elapsed());
+        new_state
+    }
+
+    /// Formats the page fault latency histogram of every region, so slow regions
+    /// can be told apart.
+    pub fn fault_latency_summary(&self) -> String {
+        let mut summary = String::from("Page fault latency per region:\n");
         for region in self.mem_regions.iter() {
-            match region.page_states.get(&fault_page_addr) {
-                // Our simple PF handler has a simple strategy:
-                // There exist 4 states in which a memory page can be in:
-                // 1. Uninitialized - page was never touched
-                // 2. FromFile - the page is populated with content from snapshotted memory file
-                // 3. Removed - MADV_DONTNEED was called due to balloon inflation
-                // 4. Anonymous - page was zeroed out -> this implies that more than one page fault
-                //    event was received. This can be a consequence of guest reclaiming back its
-                //    memory from the host (through balloon device)
-                Some(MemPageState::Uninitialized) | Some(MemPageState::FromFile) => {
-                    let (start, end) = self.populate_from_file(region, fault_page_addr, len);
-                    self.update_mem_state_mappings(start, end, MemPageState::FromFile);
-                    return;
-                }
-                Some(MemPageState::Removed) | Some(MemPageState::Anonymous) => {
-                    let (start, end) = self.zero_out(fault_page_addr);
-                    self.update_mem_state_mappings(start, end, MemPageState::Anonymous);
-                    return;
-                }
-                None => {}
+            summary.push_str(&format!(
+                "  guest address {:#x}, {} bytes:",
+                region.mapping.base_guest_phys_addr, region.mapping.size
+            ));
+            for (label, count) in LATENCY_BUCKET_LABELS
+                .iter()
+                .zip(region.fault_latency.counts())
+            {
+                summary.push_str(&format!(" {label}: {count}"));
             }
+            summary.push('\n');
         }
+        summary
+    }
 
-        panic!(
-            "Could not find addr: {:?} within guest region mappings.",
-            addr
-        );
+    /// Logs and counts an event the handler doesn't know how to serve, instead of
+    /// bringing the handler down.
+    pub fn handle_unexpected_event(&self, event: Event) {
+        let name = match event {
+            Event::Pagefault { .. } => "pagefault",
+            Event::Fork { .. } => "fork",
+            Event::Remap { .. } => "remap",
+            Event::Remove { .. } => "remove",
+            Event::Unmap { .. } => "unmap",
+        };
+        eprintln!("Ignoring unexpected event on userfaultfd: {event:?}");
+        self.count_unhandled_event(name);
+    }
+
+    /// Handles an error returned by [`UffdHandler::read_event`]. Events of a type the
+    /// `userfaultfd` crate doesn't know about are logged and counted as "unknown", like
+    /// other unexpected events. Any other error is fatal.
+    pub fn handle_read_error(&self, err: Error) {
+        match err {
+            Error::UnrecognizedEvent(_) => {
+                eprintln!("Ignoring event on userfaultfd: {err}");
+                self.count_unhandled_event("unknown");
+            }
+            err => panic!("Failed to read uffd_msg: {err}"),
+        }
     }
 
-    fn populate_from_file(&self, region: &MemRegion, dst: u64, len: usize) -> (u64, u64) {
-        let offset = dst - region.mapping.base_host_virt_addr;
-        let src = self.backing_buffer as u64 + region.mapping.offset + offset;
+    fn count_unhandled_event(&self, name: &'static str) {
+        let mut unhandled_events = self.unhandled_events.lock().unwrap();
+        *unhandled_events.entry(name).or_default() += 1;
+    }
 
-        let ret = unsafe {
-            self.uffd
-                .copy(src as *const _, dst as *mut _, len, true)
-                .expect("Uffd copy failed")
+    /// Number of unexpected events received so far, per event type.
+    pub fn unhandled_events(&self) -> BTreeMap<&'static str, usize> {
+        self.unhandled_events.lock().unwrap().clone()
+    }
+
+    /// Returns where the contents of the host virtual address `addr` should be copied
+    /// from, along with the number of bytes from `addr` on that come from the same
+    /// memory file and region.
+    fn source_for(&self, addr: u64) -> Option<(*const u8, u64)> {
+        let region = self.region_for(addr)?;
+        let region_offset = addr - region.mapping.base_host_virt_addr;
+        let region_left = region.mapping.size as u64 - region_offset;
+        let guest_addr = region.mapping.base_guest_phys_addr + region_offset;
+        // Distance to the next of `overlays` starting after the address, as their
+        // contents take precedence from there on.
+        let until_next = |overlays: &[Overlay], len: u64| {
+            overlays
+                .iter()
+                .filter(|overlay| overlay.guest_range.start > guest_addr)
+                .map(|overlay| overlay.guest_range.start - guest_addr)
+                .fold(len, u64::min)
         };
 
-        // Make sure the UFFD copied some bytes.
-        assert!(ret > 0);
+        // The first overlay covering the address wins, until one listed before it starts.
+        if let Some(index) = self
+            .overlays
+            .iter()
+            .position(|overlay| overlay.guest_range.contains(&guest_addr))
+        {
+            let overlay = &self.overlays[index];
+            let offset = usize::try_from(guest_addr - overlay.guest_range.start).unwrap();
+            let len = until_next(
+                &self.overlays[..index],
+                (overlay.guest_range.end - guest_addr).min(region_left),
+            );
+            return Some((overlay.buffer.wrapping_add(offset), len));
+        }
 
-        (dst, dst + len as u64)
+        let len = until_next(&self.overlays, region_left);
+        let offset = usize::try_from(self.addr_to_offset(addr)?).unwrap();
+        Some((self.backing_buffer.wrapping_add(offset), len))
     }
 
-    fn zero_out(&mut self, addr: u64) -> (u64, u64) {
-        let ret = unsafe {
-            self.uffd
-                .zeropage(addr as *mut _, self.page_size, true)
-                .expect("Uffd zeropage failed")
-        };
-        // Make sure the UFFD zeroed out some bytes.
-        assert!(ret > 0);
+    fn populate_from_file(&self, dst: u64, len: usize) -> (u64, u64) {
+        let end = dst + len as u64;
+        let mut addr = dst;
+        // The range may span several memory files, copy it piece by piece.
+        while addr < end {
+            let (src, contiguous_len) = self
+                .source_for(addr)
+                .expect("Address is not within guest region mappings");
+            let chunk_len = contiguous_len.min(end - addr);
+            self.copy(src, addr, usize::try_from(chunk_len).unwrap());
+            addr += chunk_len;
+        }
+
+        (dst, end)
+    }
+
+    fn copy(&self, src: *const u8, dst: u64, len: usize) {
+        // # Safety:
+        // `src` lies within a memory file mapping and `dst` within a registered region.
+        match unsafe { self.uffd.copy(src.cast(), dst as *mut _, len, true) } {
+            // Make sure the UFFD copied some bytes.
+            Ok(ret) => assert!(ret > 0),
+            // The page was already populated, e.g. by another worker serving a fault
+            // for the same page.
+            Err(Error::CopyFailed(errno))
+                if std::io::Error::from(errno).raw_os_error() == Some(libc::EEXIST) => {}
+            Err(err) => panic!("Uffd copy failed: {err:?}"),
+        }
+    }
+
+    fn zero_out(&self, addr: u64) -> (u64, u64) {
+        // # Safety:
+        // `addr` lies within a registered region.
+        match unsafe { self.uffd.zeropage(addr as *mut _, self.page_size, true) } {
+            // Make sure the UFFD zeroed out some bytes.
+            Ok(ret) => assert!(ret > 0),
+            // The page was already populated, e.g. by another worker serving a fault
+            // for the same page.
+            Err(Error::ZeropageFailed(errno))
+                if std::io::Error::from(errno).raw_os_error() == Some(libc::EEXIST) => {}
+            Err(err) => panic!("Uffd zeropage failed: {err:?}"),
+        }
 
         (addr, addr + self.page_size as u64)
     }
 }
 
+/// Unix domain socket listener on which Firecracker sends the uffd. The socket
+/// file is removed when the listener is dropped, or when the handler is
+/// terminated after calling [`UdsListener::exit_on_signals`], so a new handler
+/// can bind to the same path.
+#[derive(Debug)]
+pub struct UdsListener {
+    listener: UnixListener,
+    path: PathBuf,
+    // Locked for as long as the handler lives, see `UdsListener::bind`.
+    lock: File,
+    lock_path: PathBuf,
+}
+
+impl UdsListener {
+    /// Binds to `path`, removing a stale socket left there by a handler that
+    /// didn't get to clean up. A socket belonging to a live handler is left
+    /// untouched.
+    ///
+    /// A live handler holds a lock on `<path>.lock`, which gets released however
+    /// the handler exits. Only if the lock can be taken is the socket probed, so a
+    /// live handler doesn't take the probe for Firecracker's connection, while a
+    /// socket something else listens on isn't mistaken for a stale one.
+    pub fn bind(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        let lock = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .expect("Cannot open socket lock file");
+        // # Safety:
+        // The fd is valid.
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            match std::io::Error::last_os_error() {
+                err if err.kind() == ErrorKind::WouldBlock => {
+                    panic!("Socket {} is already in use", path.display())
+                }
+                err => panic!("Cannot lock {}: {err}", lock_path.display()),
+            }
+        }
+
+        if is_listening(path) {
+            panic!("Socket {} is already in use", path.display());
+        }
+        match std::fs::remove_file(path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => panic!("Cannot remove stale socket {}: {err}", path.display()),
+        }
+        let listener = UnixListener::bind(path).expect("Cannot bind to socket path");
+
+        Self {
+            listener,
+            path: path.to_path_buf(),
+            lock,
+            lock_path,
+        }
+    }
+
+    /// Waits for Firecracker to connect.
+    pub fn accept(&self) -> UnixStream {
+        let (stream, _) = self.listener.accept().expect("Cannot listen on UDS socket");
+        stream
+    }
+
+    /// Makes the handler exit when it receives `SIGINT` or `SIGTERM`, after calling
+    /// `on_exit` and removing the socket.
+    ///
+    /// The signal handler only passes the signal on to a dedicated thread, so `on_exit`
+    /// is free to print or lock. Can only be called once per process.
+    pub fn exit_on_signals(&self, on_exit: impl FnOnce() + Send + 'static) {
+        let mut fds = [0; 2];
+        // # Safety:
+        // `fds` has room for both ends of the pipe.
+        let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+        assert_eq!(ret, 0, "Cannot create exit signal pipe");
+        // # Safety:
+        // The read end of the pipe was just created and isn't owned by anything else.
+        let mut signals = unsafe { File::from_raw_fd(fds[0]) };
+        // The write end stays open for as long as the process lives.
+        if EXIT_SIGNAL_FD
+            .compare_exchange(-1, fds[1], Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            panic!("Exit signals are already handled");
+        }
+        for signum in [libc::SIGINT, libc::SIGTERM] {
+            register_signal_handler(signum, notify_exit_signal)
+                .expect("Cannot register exit signal handler");
+        }
+
+        let path = self.path.clone();
+        let lock_path = self.lock_path.clone();
+        thread::spawn(move || {
+            let mut signum = [0; std::mem::size_of::<libc::c_int>()];
+            signals
+                .read_exact(&mut signum)
+                .expect("Cannot wait for exit signals");
+
+            on_exit();
+            let _ = std::fs::remove_file(&path);
+            let _ = std::fs::remove_file(&lock_path);
+            // Exiting doesn't flush stdout.
+            let _ = std::io::stdout().flush();
+            std::process::exit(128 + libc::c_int::from_ne_bytes(signum));
+        });
+    }
+}
+
+/// Checks whether something listens on the socket at `path`, without waiting for it
+/// to accept the connection.
+fn is_listening(path: &Path) -> bool {
+    // # Safety:
+    // Creating a socket has no memory safety implications.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_UNIX,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    assert!(fd >= 0, "Cannot create socket");
+    // # Safety:
+    // The socket was just created and isn't owned by anything else.
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // # Safety:
+    // `sockaddr_un` is a plain C struct, for which all zeroes is a valid value.
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_family = libc::sa_family_t::try_from(libc::AF_UNIX).unwrap();
+    let path_bytes = path.as_os_str().as_bytes();
+    // The path has to be NUL terminated.
+    assert!(
+        path_bytes.len() < addr.sun_path.len(),
+        "Socket path {} is too long",
+        path.display()
+    );
+    for (dst, src) in addr.sun_path.iter_mut().zip(path_bytes) {
+        *dst = libc::c_char::from_ne_bytes([*src]);
+    }
+
+    // # Safety:
+    // `addr` is a valid address of the given length.
+    let ret = unsafe {
+        libc::connect(
+            socket.as_raw_fd(),
+            ptr::addr_of!(addr).cast(),
+            libc::socklen_t::try_from(std::mem::size_of::<libc::sockaddr_un>()).unwrap(),
+        )
+    };
+    if ret == 0 {
+        return true;
+    }
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        // Nothing listens on the socket, or there is no socket at all.
+        Some(libc::ECONNREFUSED | libc::ENOENT) => false,
+        // The listener's backlog is full.
+        Some(libc::EAGAIN) => true,
+        _ => panic!("Cannot probe socket {}: {err}", path.display()),
+    }
+}
+
+/// Write end of the pipe on which [`UdsListener::exit_on_signals`] gets the exit signals.
+static EXIT_SIGNAL_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn notify_exit_signal(
+    num: libc::c_int,
+    _info: *mut libc::siginfo_t,
+    _: *mut libc::c_void,
+) {
+    // # Safety:
+    // `write` is async-signal-safe and `num` outlives the call.
+    unsafe {
+        libc::write(
+            EXIT_SIGNAL_FD.load(Ordering::SeqCst),
+            ptr::addr_of!(num).cast(),
+            std::mem::size_of::<libc::c_int>(),
+        )
+    };
+}
+
+impl Drop for UdsListener {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+        let _ = std::fs::remove_file(&self.lock_path);
+    }
+}
+
+/// Maps `file` read-only in the address space of the handler.
+fn mmap_file(file: &File) -> (*mut u8, usize) {
+    let file_meta = file.metadata().expect("can not get backing file metadata");
+    let size = file_meta.len() as usize;
+    // # Safety:
+    // File size and fd are valid
+    let ret = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if ret == libc::MAP_FAILED {
+        panic!("mmap on backing file failed");
+    }
+    (ret.cast(), size)
+}
+
+/// Size of the readahead requests issued when prefetching the memory file.
+const PREFETCH_CHUNK_SIZE: usize = 2 << 20;
+
 #[derive(Debug)]
 pub struct Runtime {
     stream: UnixStream,
     backing_file: File,
     backing_memory: *mut u8,
     backing_memory_size: usize,
-    uffds: HashMap<i32, UffdHandler>,
+    overlays: Vec<Overlay>,
+    // Kept open to be prefetched, along with their size.
+    overlay_files: Vec<(File, usize)>,
+    uffds: Arc<RwLock<HashMap<i32, Arc<UffdHandler>>>>,
+}
+
+/// Handle on the uffd handlers of a [`Runtime`], usable from other threads
+/// while the runtime is running, e.g. to report statistics.
+#[derive(Debug, Clone)]
+pub struct UffdHandlers(Arc<RwLock<HashMap<i32, Arc<UffdHandler>>>>);
+
+impl UffdHandlers {
+    /// Returns the handlers of the uffds received so far.
+    pub fn get(&self) -> Vec<Arc<UffdHandler>> {
+        self.0.read().unwrap().values().cloned().collect()
+    }
 }
 
 impl Runtime {
     pub fn new(stream: UnixStream, backing_file: File) -> Self {
-        let file_meta = backing_file
-            .metadata()
-            .expect("can not get backing file metadata");
-        let backing_memory_size = file_meta.len() as usize;
-        // # Safety:
-        // File size and fd are valid
-        let ret = unsafe {
-            libc::mmap(
-                ptr::null_mut(),
-                backing_memory_size,
-                libc::PROT_READ,
-                libc::MAP_PRIVATE,
-                backing_file.as_raw_fd(),
-                0,
-            )
-        };
-        if ret == libc::MAP_FAILED {
-            panic!("mmap on backing file failed");
+        Self::with_overlays(stream, backing_file, Vec::new())
+    }
+
+    /// Creates a runtime serving guest memory from `backing_file`, except for the
+    /// guest physical address ranges covered by `overlays`, whose contents come from
+    /// the associated file instead. When overlays overlap, the first one wins.
+    ///
+    /// This allows serving snapshots split across several files, e.g. a diff
+    /// snapshot memory file on top of the full snapshot one.
+    pub fn with_overlays(
+        stream: UnixStream,
+        backing_file: File,
+        overlays: Vec<(File, Range<u64>)>,
+    ) -> Self {
+        let (backing_memory, backing_memory_size) = mmap_file(&backing_file);
+
+        let mut overlay_mappings = Vec::with_capacity(overlays.len());
+        let mut overlay_files = Vec::with_capacity(overlays.len());
+        for (file, guest_range) in overlays {
+            let (buffer, size) = mmap_file(&file);
+            assert!(
+                guest_range.end - guest_range.start <= size as u64,
+                "Overlay file is smaller than its range {guest_range:#x?}"
+            );
+            overlay_mappings.push(Overlay {
+                guest_range,
+                buffer: buffer.cast_const(),
+            });
+            overlay_files.push((file, size));
         }
 
         Self {
             stream,
             backing_file,
-            backing_memory: ret.cast(),
+            backing_memory,
             backing_memory_size,
-            uffds: HashMap::default(),
+            overlays: overlay_mappings,
+            overlay_files,
+            uffds: Arc::default(),
         }
     }
 
+    /// Returns a handle on the handlers of the uffds received by the runtime.
+    pub fn handlers(&self) -> UffdHandlers {
+        UffdHandlers(Arc::clone(&self.uffds))
+    }
+
+    /// Total size of the memory files guest memory is served from, i.e. the base
+    /// memory file and the overlays.
+    pub fn memory_files_size(&self) -> usize {
+        self.backing_memory_size
+            + self
+                .overlay_files
+                .iter()
+                .map(|(_, size)| size)
+                .sum::<usize>()
+    }
+
+    /// Starts a thread sequentially asking the kernel to read the memory files ahead,
+    /// the base one first and then the overlays, so that their contents are already in
+    /// the page cache when page faults get served instead of being read from disk on
+    /// the fault path. Returns the number of bytes requested so far, which reaches
+    /// [`Runtime::memory_files_size`] once done. The last reads may still be in flight
+    /// by then.
+    ///
+    /// The thread only uses its own fds, it doesn't touch the mappings page faults are
+    /// copied from nor the uffds, so it can't get in the way of faults served
+    /// concurrently: a fault on a page not prefetched yet is read from disk as usual.
+    pub fn prefetch_backing_file(&self) -> Arc<AtomicUsize> {
+        let files: Vec<(File, usize)> =
+            std::iter::once((&self.backing_file, self.backing_memory_size))
+                .chain(self.overlay_files.iter().map(|(file, size)| (file, *size)))
+                .map(|(file, size)| (file.try_clone().expect("Cannot clone memory file"), size))
+                .collect();
+        let progress = Arc::new(AtomicUsize::new(0));
+        let progress_clone = Arc::clone(&progress);
+
+        thread::spawn(move || {
+            let mut done = 0;
+            for (file, size) in files {
+                let mut offset = 0;
+                while offset < size {
+                    let len = PREFETCH_CHUNK_SIZE.min(size - offset);
+                    // # Safety:
+                    // The fd is valid.
+                    let ret = unsafe {
+                        libc::readahead(file.as_raw_fd(), i64::try_from(offset).unwrap(), len)
+                    };
+                    assert_eq!(ret, 0, "Cannot prefetch memory file");
+                    offset += len;
+                    done += len;
+                    progress_clone.store(done, Ordering::SeqCst);
+                }
+            }
+        });
+
+        progress
+    }
+
     /// Polls the `UnixStream` and UFFD fds in a loop.
     /// When stream is polled, new uffd is retrieved.
     /// When uffd is polled, page fault is handled by
     /// calling `pf_event_dispatch` with corresponding
     /// uffd object passed in.
-    pub fn run(&mut self, pf_event_dispatch: impl Fn(&mut UffdHandler)) {
+    pub fn run(&mut self, pf_event_dispatch: impl Fn(&UffdHandler)) {
         let mut pollfds = vec![];
 
         // Poll the stream for incoming uffds
@@ -250,13 +891,17 @@ impl Runtime {
                             &self.stream,
                             self.backing_memory,
                             self.backing_memory_size,
+                            self.overlays.clone(),
                         );
                         pollfds.push(libc::pollfd {
                             fd: handler.uffd.as_raw_fd(),
                             events: libc::POLLIN,
                             revents: 0,
                         });
-                        self.uffds.insert(handler.uffd.as_raw_fd(), handler);
+                        self.uffds
+                            .write()
+                            .unwrap()
+                            .insert(handler.uffd.as_raw_fd(), Arc::new(handler));
 
                         // If connection is closed, we can skip the socket from being polled.
                         if pollfds[i].revents & (libc::POLLRDHUP | libc::POLLHUP) != 0 {
@@ -264,14 +909,157 @@ impl Runtime {
                         }
                     } else {
                         // Handle one of uffd page faults
-                        pf_event_dispatch(self.uffds.get_mut(&pollfds[i].fd).unwrap());
+                        pf_event_dispatch(&self.uffds.read().unwrap()[&pollfds[i].fd]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Same as [`Runtime::run`], but page faults are served by a pool of
+    /// `num_workers` threads instead of the calling thread, which is useful
+    /// to speed up the restore of large guests faulting from several vCPUs.
+    ///
+    /// The userfaultfd API allows issuing `UFFDIO_COPY`/`UFFDIO_ZEROPAGE`
+    /// concurrently, so every worker polls all uffds and handles the events it
+    /// manages to read, passing them to `pf_event_dispatch`. The calling thread
+    /// keeps polling the `UnixStream` and hands new uffds over to the workers.
+    ///
+    /// Page faults are dispatched concurrently, but any other event (e.g.
+    /// `Remove`) is dispatched alone, after the events read before it and
+    /// before the ones read after it.
+    pub fn run_parallel(
+        &mut self,
+        num_workers: usize,
+        pf_event_dispatch: impl Fn(&UffdHandler, Event) + Sync,
+    ) {
+        assert!(num_workers > 0, "At least one worker is needed");
+
+        // Each worker gets notified through its own eventfd when a new uffd comes in.
+        let wakeups: Vec<EventFd> = (0..num_workers)
+            .map(|_| EventFd::new(EFD_NONBLOCK).expect("Cannot create eventfd"))
+            .collect();
+        let uffds = &self.uffds;
+        let pf_event_dispatch = &pf_event_dispatch;
+
+        thread::scope(|s| {
+            for wakeup in wakeups.iter() {
+                s.spawn(move || worker_loop(uffds, wakeup, pf_event_dispatch));
+            }
+
+            let mut pollfd = libc::pollfd {
+                fd: self.stream.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            loop {
+                // # Safety:
+                // Pollfd is valid
+                let nready = unsafe { libc::poll(&mut pollfd, 1, -1) };
+                if nready == -1 {
+                    panic!("Could not poll for events!")
+                }
+                if pollfd.revents & libc::POLLIN != 0 {
+                    // Handle new uffd from stream
+                    let handler = UffdHandler::from_unix_stream(
+                        &self.stream,
+                        self.backing_memory,
+                        self.backing_memory_size,
+                        self.overlays.clone(),
+                    );
+                    self.uffds
+                        .write()
+                        .unwrap()
+                        .insert(handler.uffd.as_raw_fd(), Arc::new(handler));
+                    for wakeup in wakeups.iter() {
+                        wakeup.write(1).expect("Cannot notify worker");
+                    }
+
+                    // If connection is closed, we can stop polling the socket and
+                    // leave the rest to the workers.
+                    if pollfd.revents & (libc::POLLRDHUP | libc::POLLHUP) != 0 {
+                        break;
                     }
                 }
             }
+        });
+    }
+}
+
+/// Serves page faults on all the uffds known to the runtime, see `Runtime::run_parallel`.
+fn worker_loop(
+    uffds: &RwLock<HashMap<i32, Arc<UffdHandler>>>,
+    wakeup: &EventFd,
+    pf_event_dispatch: &impl Fn(&UffdHandler, Event),
+) {
+    loop {
+        let handlers: Vec<Arc<UffdHandler>> = uffds.read().unwrap().values().cloned().collect();
+
+        let mut pollfds = Vec::with_capacity(handlers.len() + 1);
+        pollfds.push(libc::pollfd {
+            fd: wakeup.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        pollfds.extend(handlers.iter().map(|handler| libc::pollfd {
+            fd: handler.uffd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }));
+
+        // # Safety:
+        // Pollfds vector is valid
+        let nready = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as u64, -1) };
+        if nready == -1 {
+            panic!("Could not poll for events!")
         }
+
+        if pollfds[0].revents & libc::POLLIN != 0 {
+            // A new uffd was received, refresh the handlers before polling again.
+            wakeup.read().expect("Cannot read worker eventfd");
+            continue;
+        }
+
+        for (pollfd, handler) in pollfds[1..].iter().zip(handlers.iter()) {
+            if pollfd.revents & libc::POLLIN != 0 {
+                // All workers are woken up for the same event, so another worker
+                // may have already read it.
+                match handler.read_event_ordered() {
+                    Ok(Some((event, _guard))) => pf_event_dispatch(handler, event),
+                    Ok(None) => {}
+                    Err(err) => handler.handle_read_error(err),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the default page size of the host, in bytes.
+fn host_page_size() -> usize {
+    // # Safety:
+    // sysconf has no preconditions
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        -1 => panic!("Cannot get the host page size"),
+        ps => usize::try_from(ps).unwrap(),
     }
 }
 
+/// Makes sure the page size received from Firecracker can be used for
+/// `UFFDIO_COPY`/`UFFDIO_ZEROPAGE`, i.e. that it's either the host page size or
+/// a huge page size built on top of it.
+fn validate_page_size(page_size: usize) {
+    let host_page_size = host_page_size();
+    assert!(
+        page_size.is_power_of_two(),
+        "Page size {page_size} is not a power of two"
+    );
+    assert_eq!(
+        page_size % host_page_size,
+        0,
+        "Page size {page_size} is not a multiple of the host page size {host_page_size}"
+    );
+}
+
 fn create_mem_regions(mappings: &Vec<GuestRegionUffdMapping>, page_size: usize) -> Vec<MemRegion> {
     let mut mem_regions: Vec<MemRegion> = Vec::with_capacity(mappings.len());
 
@@ -287,7 +1075,8 @@ fn create_mem_regions(mappings: &Vec<GuestRegionUffdMapping>, page_size: usize)
         }
         mem_regions.push(MemRegion {
             mapping,
-            page_states,
+            page_states: Mutex::new(page_states),
+            fault_latency: LatencyHistogram::default(),
         });
     }
 
@@ -297,8 +1086,9 @@ fn create_mem_regions(mappings: &Vec<GuestRegionUffdMapping>, page_size: usize)
 #[cfg(test)]
 mod tests {
     use std::mem::MaybeUninit;
-    use std::os::unix::net::UnixListener;
+    use std::sync::Condvar;
 
+    use userfaultfd::{FeatureFlags, UffdBuilder};
     use vmm_sys_util::tempdir::TempDir;
     use vmm_sys_util::tempfile::TempFile;
 
@@ -328,7 +1118,7 @@ mod tests {
             let (stream, _) = listener.accept().expect("Cannot listen on UDS socket");
             // Update runtime with actual runtime
             let runtime = uninit_runtime.write(Runtime::new(stream, file));
-            runtime.run(|_: &mut UffdHandler| {});
+            runtime.run(|_: &UffdHandler| {});
         });
 
         // wait for runtime thread to initialize itself
@@ -339,6 +1129,7 @@ mod tests {
 
         let dummy_memory_region = vec![GuestRegionUffdMapping {
             base_host_virt_addr: 0,
+            base_guest_phys_addr: 0,
             size: 0x1000,
             offset: 0,
             page_size_kib: 4096,
@@ -353,7 +1144,7 @@ mod tests {
         // wait for the runtime thread to process message
         std::thread::sleep(std::time::Duration::from_millis(100));
         unsafe {
-            assert_eq!((*runtime_ptr).uffds.len(), 1);
+            assert_eq!((*runtime_ptr).uffds.read().unwrap().len(), 1);
         }
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -364,7 +1155,7 @@ mod tests {
         // wait for the runtime thread to process message
         std::thread::sleep(std::time::Duration::from_millis(100));
         unsafe {
-            assert_eq!((*runtime_ptr).uffds.len(), 2);
+            assert_eq!((*runtime_ptr).uffds.read().unwrap().len(), 2);
         }
 
         // there is no way to properly stop runtime, so
@@ -372,6 +1163,7 @@ mod tests {
         // to cause runtime thread to panic
         let error_memory_region = vec![GuestRegionUffdMapping {
             base_host_virt_addr: 0,
+            base_guest_phys_addr: 0,
             size: 0,
             offset: 0,
             page_size_kib: 4096,
@@ -383,4 +1175,916 @@ mod tests {
 
         runtime_thread.join().unwrap_err();
     }
+
+    fn handler_from_mappings(
+        mappings: &[GuestRegionUffdMapping],
+        backing_memory: &[u8],
+    ) -> UffdHandler {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let mappings_json = serde_json::to_string(mappings).unwrap();
+        // The handler only stores the received fd, so any file will do.
+        let dummy_file = TempFile::new().unwrap();
+        sender
+            .send_with_fd(mappings_json.as_bytes(), dummy_file.as_file().as_raw_fd())
+            .unwrap();
+
+        UffdHandler::from_unix_stream(
+            &receiver,
+            backing_memory.as_ptr(),
+            backing_memory.len(),
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn test_region_for_multiple_regions() {
+        // Two regions which are neither contiguous in the host address space
+        // nor in the guest physical address space.
+        let mappings = vec![
+            GuestRegionUffdMapping {
+                base_host_virt_addr: 0x10000,
+                base_guest_phys_addr: 0,
+                size: 0x2000,
+                offset: 0,
+                page_size_kib: 0x1000,
+            },
+            GuestRegionUffdMapping {
+                base_host_virt_addr: 0x40000,
+                base_guest_phys_addr: 0x1_0000_0000,
+                size: 0x1000,
+                offset: 0x2000,
+                page_size_kib: 0x1000,
+            },
+        ];
+        let backing_memory = vec![0u8; 0x3000];
+        let handler = handler_from_mappings(&mappings, &backing_memory);
+
+        assert_eq!(handler.mem_regions.len(), 2);
+
+        let region = handler.region_for(0x11000).unwrap();
+        assert_eq!(region.mapping.base_guest_phys_addr, 0);
+        assert_eq!(region.mapping.offset, 0);
+
+        let region = handler.region_for(0x40000).unwrap();
+        assert_eq!(region.mapping.base_guest_phys_addr, 0x1_0000_0000);
+        assert_eq!(region.mapping.offset, 0x2000);
+
+        // Addresses in the gap between the regions or past the last one
+        // don't belong to any region.
+        assert!(handler.region_for(0x12000).is_none());
+        assert!(handler.region_for(0x3f000).is_none());
+        assert!(handler.region_for(0x41000).is_none());
+    }
+
+    #[test]
+    fn test_addr_to_offset() {
+        // The second region comes first in the memory file.
+        let mappings = vec![
+            GuestRegionUffdMapping {
+                base_host_virt_addr: 0x10000,
+                base_guest_phys_addr: 0,
+                size: 0x2000,
+                offset: 0x1000,
+                page_size_kib: 0x1000,
+            },
+            GuestRegionUffdMapping {
+                base_host_virt_addr: 0x40000,
+                base_guest_phys_addr: 0x1_0000_0000,
+                size: 0x1000,
+                offset: 0,
+                page_size_kib: 0x1000,
+            },
+        ];
+        let backing_memory = vec![0u8; 0x3000];
+        let handler = handler_from_mappings(&mappings, &backing_memory);
+
+        assert_eq!(handler.addr_to_offset(0x10000), Some(0x1000));
+        assert_eq!(handler.addr_to_offset(0x11000), Some(0x2000));
+        assert_eq!(handler.addr_to_offset(0x11fff), Some(0x2fff));
+        assert_eq!(handler.addr_to_offset(0x40000), Some(0));
+        assert_eq!(handler.addr_to_offset(0x40800), Some(0x800));
+
+        // Gap between the two regions.
+        assert_eq!(handler.addr_to_offset(0x12000), None);
+        assert_eq!(handler.addr_to_offset(0x30000), None);
+        // Before the first and after the last region.
+        assert_eq!(handler.addr_to_offset(0xf000), None);
+        assert_eq!(handler.addr_to_offset(0x41000), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a multiple of the host page size")]
+    fn test_page_size_smaller_than_host() {
+        let page_size = host_page_size() / 2;
+        let mappings = vec![GuestRegionUffdMapping {
+            base_host_virt_addr: 0,
+            base_guest_phys_addr: 0,
+            size: page_size,
+            offset: 0,
+            page_size_kib: page_size,
+        }];
+        let backing_memory = vec![0u8; page_size];
+        handler_from_mappings(&mappings, &backing_memory);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a power of two")]
+    fn test_page_size_not_power_of_two() {
+        let page_size = host_page_size() * 3;
+        let mappings = vec![GuestRegionUffdMapping {
+            base_host_virt_addr: 0,
+            base_guest_phys_addr: 0,
+            size: page_size,
+            offset: 0,
+            page_size_kib: page_size,
+        }];
+        let backing_memory = vec![0u8; page_size];
+        handler_from_mappings(&mappings, &backing_memory);
+    }
+
+    /// Maps `mem_size` bytes of anonymous memory standing for the guest memory,
+    /// registers it with a new uffd supporting `features` and sends the uffd to
+    /// the runtime at the other end of `sender`, as a single region starting at
+    /// guest physical address 0 and backed by the start of the memory file.
+    fn register_guest_memory(
+        sender: &UnixStream,
+        mem_size: usize,
+        features: FeatureFlags,
+    ) -> (*mut libc::c_void, Uffd) {
+        // # Safety:
+        // Anonymous mapping with valid arguments.
+        let guest_memory = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                mem_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(guest_memory, libc::MAP_FAILED);
+        let uffd = UffdBuilder::new()
+            .close_on_exec(true)
+            .non_blocking(true)
+            .user_mode_only(true)
+            .require_features(features)
+            .create()
+            .unwrap();
+        uffd.register(guest_memory, mem_size).unwrap();
+
+        let mappings = vec![GuestRegionUffdMapping {
+            base_host_virt_addr: guest_memory as u64,
+            base_guest_phys_addr: 0,
+            size: mem_size,
+            offset: 0,
+            page_size_kib: host_page_size(),
+        }];
+        sender
+            .send_with_fd(
+                serde_json::to_string(&mappings).unwrap().as_bytes(),
+                uffd.as_raw_fd(),
+            )
+            .unwrap();
+
+        (guest_memory, uffd)
+    }
+
+    /// Writes `contents` to a new file and evicts it from the page cache, so
+    /// that reading it back has to go to the disk.
+    fn cold_file(contents: &[u8]) -> TempFile {
+        let file = TempFile::new().unwrap();
+        file.as_file().write_all(contents).unwrap();
+        file.as_file().sync_all().unwrap();
+        // # Safety:
+        // The fd is valid.
+        let ret = unsafe {
+            libc::posix_fadvise(file.as_file().as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED)
+        };
+        assert_eq!(ret, 0, "Cannot evict file from the page cache");
+        file
+    }
+
+    /// Returns the number of pages of `file` which are in the page cache.
+    fn cached_pages(file: &File) -> usize {
+        let (addr, size) = mmap_file(file);
+        let mut residency = vec![0u8; size.div_ceil(host_page_size())];
+        // # Safety:
+        // `residency` has an entry for every page of the mapping.
+        let ret = unsafe { libc::mincore(addr.cast(), size, residency.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        // # Safety:
+        // The mapping was created above and is no longer used.
+        unsafe { libc::munmap(addr.cast(), size) };
+        residency.iter().filter(|page| *page & 1 != 0).count()
+    }
+
+    /// Restores a guest memory region through a `Runtime` using `num_workers`
+    /// threads, touches all of its pages from several threads and returns how
+    /// long it took. `before_serve` is called before serving each page fault, and
+    /// the guest runs for `boot_time` before touching its memory.
+    fn time_restore(
+        num_workers: usize,
+        prefetch: bool,
+        before_serve: impl Fn() + Send + Sync + 'static,
+        boot_time: Duration,
+    ) -> Duration {
+        const TOUCHING_THREADS: usize = 4;
+        let page_size = host_page_size();
+        let num_pages = 4096;
+        let mem_size = num_pages * page_size;
+
+        // Simulate a cold restore.
+        let contents: Vec<u8> = (0..num_pages)
+            .flat_map(|page| vec![u8::try_from(page % 256).unwrap(); page_size])
+            .collect();
+        let memory_file = cold_file(&contents);
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let mut runtime = Runtime::new(receiver, memory_file.as_file().try_clone().unwrap());
+        if prefetch {
+            // Faults get served while the prefetch is still in progress.
+            runtime.prefetch_backing_file();
+        }
+        let serve = move |uffd_handler: &UffdHandler, event: Event| {
+            if let Event::Pagefault { addr, .. } = event {
+                before_serve();
+                uffd_handler.serve_pf(addr.cast(), uffd_handler.page_size);
+            }
+        };
+        std::thread::spawn(move || {
+            if num_workers > 1 {
+                runtime.run_parallel(num_workers, serve);
+            } else {
+                runtime.run(|uffd_handler: &UffdHandler| {
+                    if let Some(event) = uffd_handler.read_event().unwrap() {
+                        serve(uffd_handler, event);
+                    }
+                });
+            }
+        });
+
+        let (guest_memory, _uffd) = register_guest_memory(&sender, mem_size, FeatureFlags::empty());
+
+        std::thread::sleep(boot_time);
+        let guest_memory_addr = guest_memory as usize;
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            for thread_idx in 0..TOUCHING_THREADS {
+                s.spawn(move || {
+                    for page in (thread_idx..num_pages).step_by(TOUCHING_THREADS) {
+                        let addr = (guest_memory_addr + page * page_size) as *const u8;
+                        // # Safety:
+                        // The address is within the registered mapping.
+                        let byte = unsafe { std::ptr::read_volatile(addr) };
+                        assert_eq!(byte, u8::try_from(page % 256).unwrap());
+                    }
+                });
+            }
+        });
+        let elapsed = start.elapsed();
+
+        // # Safety:
+        // The mapping was created above and is no longer used.
+        unsafe { libc::munmap(guest_memory, mem_size) };
+        elapsed
+    }
+
+    #[derive(Debug, Default)]
+    struct GateState {
+        /// Callers currently held back.
+        waiting: usize,
+        /// Whether enough callers were held back at the same time to let everyone through.
+        open: bool,
+        /// Whether a caller gave up waiting, after which nobody is held back anymore.
+        gave_up: bool,
+    }
+
+    /// Holds back its callers until `count` of them are waiting at the same time, then lets
+    /// them and every later caller through.
+    #[derive(Debug)]
+    struct ConcurrencyGate {
+        count: usize,
+        state: Mutex<GateState>,
+        opened: Condvar,
+    }
+
+    impl ConcurrencyGate {
+        fn new(count: usize) -> Self {
+            Self {
+                count,
+                state: Mutex::new(GateState::default()),
+                opened: Condvar::new(),
+            }
+        }
+
+        /// Waits for the gate to open. Callers give up after a while rather than hang the
+        /// test, which then finds the gate closed.
+        fn wait(&self) {
+            let mut state = self.state.lock().unwrap();
+            if state.open || state.gave_up {
+                return;
+            }
+            state.waiting += 1;
+            if state.waiting == self.count {
+                state.open = true;
+                self.opened.notify_all();
+                return;
+            }
+            let (mut state, result) = self
+                .opened
+                .wait_timeout_while(state, Duration::from_secs(10), |state| !state.open)
+                .unwrap();
+            state.waiting -= 1;
+            state.gave_up |= result.timed_out();
+        }
+
+        fn is_open(&self) -> bool {
+            self.state.lock().unwrap().open
+        }
+    }
+
+    #[test]
+    fn test_run_parallel() {
+        // Each touching thread has a single fault in flight, and none of them gets served
+        // until all 4 are being served at the same time. That only happens if the workers
+        // serve the faults concurrently.
+        let gate = Arc::new(ConcurrencyGate::new(4));
+        let before_serve = {
+            let gate = gate.clone();
+            move || gate.wait()
+        };
+        time_restore(4, false, before_serve, Duration::ZERO);
+        assert!(gate.is_open(), "Page faults were not served concurrently");
+    }
+
+    #[test]
+    fn test_run_parallel_remove() {
+        // A page removed by the balloon must be zeroed when faulted in again, even
+        // when the fault and the `Remove` event are read by different workers.
+        let page_size = host_page_size();
+        let num_pages = 16;
+        let mem_size = num_pages * page_size;
+
+        let memory_file = TempFile::new().unwrap();
+        memory_file.as_file().write_all(&vec![1; mem_size]).unwrap();
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let mut runtime = Runtime::new(receiver, memory_file.as_file().try_clone().unwrap());
+        std::thread::spawn(move || {
+            runtime.run_parallel(4, |uffd_handler: &UffdHandler, event: Event| match event {
+                Event::Pagefault { addr, .. } => {
+                    uffd_handler.serve_pf(addr.cast(), uffd_handler.page_size);
+                }
+                Event::Remove { start, end } => uffd_handler.update_mem_state_mappings(
+                    start as u64,
+                    end as u64,
+                    MemPageState::Removed,
+                ),
+                event => uffd_handler.handle_unexpected_event(event),
+            });
+        });
+
+        let (guest_memory, _uffd) =
+            register_guest_memory(&sender, mem_size, FeatureFlags::EVENT_REMOVE);
+
+        for page in 0..num_pages {
+            for round in 0..8 {
+                let expected = if round == 0 { 1 } else { 0 };
+                let addr = (guest_memory as usize + page * page_size) as *const u8;
+                // # Safety:
+                // The address is within the registered mapping.
+                let byte = unsafe { std::ptr::read_volatile(addr) };
+                assert_eq!(
+                    byte, expected,
+                    "Wrong contents for page {page} in round {round}"
+                );
+                // Remove the page right after it was faulted in, like the balloon does, so
+                // the next round faults it in again.
+                // # Safety:
+                // The range is within the registered mapping.
+                let ret = unsafe { libc::madvise(addr as *mut _, page_size, libc::MADV_DONTNEED) };
+                assert_eq!(ret, 0);
+            }
+        }
+
+        // # Safety:
+        // The mapping was created above and is no longer used.
+        unsafe { libc::munmap(guest_memory, mem_size) };
+    }
+
+    /// Whether `cold_file` can evict files from the page cache, which isn't the case
+    /// e.g. on tmpfs.
+    fn can_evict_files() -> bool {
+        let probe = cold_file(&vec![0; host_page_size()]);
+        cached_pages(probe.as_file()) == 0
+    }
+
+    /// Waits for the prefetch `progress` to reach `total` and for all the pages of
+    /// `files` to be in the page cache, as the last reads may still be in flight
+    /// once the whole files were requested.
+    fn wait_prefetched(progress: &AtomicUsize, total: usize, files: &[&File]) {
+        let expected: Vec<_> = files
+            .iter()
+            .map(|file| {
+                usize::try_from(file.metadata().unwrap().len())
+                    .unwrap()
+                    .div_ceil(host_page_size())
+            })
+            .collect();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let done = progress.load(Ordering::SeqCst);
+            let cached: Vec<_> = files.iter().map(|file| cached_pages(file)).collect();
+            if done == total && cached == expected {
+                return;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Prefetched {done} of {total} bytes, {cached:?} of {expected:?} pages are cached"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_prefetch_backing_file() {
+        if !can_evict_files() {
+            println!("Files can't be evicted from the page cache, skipping");
+            return;
+        }
+
+        let page_size = host_page_size();
+        let num_pages = 4096;
+        let mem_size = num_pages * page_size;
+        let contents: Vec<u8> = (0..num_pages)
+            .flat_map(|page| vec![u8::try_from(page % 256).unwrap(); page_size])
+            .collect();
+        let memory_file = cold_file(&contents);
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let mut runtime = Runtime::new(receiver, memory_file.as_file().try_clone().unwrap());
+        let progress = runtime.prefetch_backing_file();
+        std::thread::spawn(move || {
+            runtime.run_parallel(4, |uffd_handler: &UffdHandler, event: Event| {
+                if let Event::Pagefault { addr, .. } = event {
+                    uffd_handler.serve_pf(addr.cast(), uffd_handler.page_size);
+                }
+            });
+        });
+
+        // Faults get served while the prefetch is in progress. Only the start of the
+        // memory is touched, the rest of the file has to be brought in by the prefetch.
+        let (guest_memory, _uffd) = register_guest_memory(&sender, mem_size, FeatureFlags::empty());
+        for page in 0..num_pages / 4 {
+            let addr = (guest_memory as usize + page * page_size) as *const u8;
+            // # Safety:
+            // The address is within the registered mapping.
+            let byte = unsafe { std::ptr::read_volatile(addr) };
+            assert_eq!(byte, u8::try_from(page % 256).unwrap());
+        }
+
+        wait_prefetched(&progress, mem_size, &[memory_file.as_file()]);
+
+        // # Safety:
+        // The mapping was created above and is no longer used.
+        unsafe { libc::munmap(guest_memory, mem_size) };
+    }
+
+    #[test]
+    fn test_prefetch_overlays() {
+        if !can_evict_files() {
+            println!("Files can't be evicted from the page cache, skipping");
+            return;
+        }
+
+        let page_size = host_page_size();
+        let base_file = cold_file(&vec![1; 1024 * page_size]);
+        let overlay_file = cold_file(&vec![2; 512 * page_size]);
+        let (_sender, receiver) = UnixStream::pair().unwrap();
+        let runtime = Runtime::with_overlays(
+            receiver,
+            base_file.as_file().try_clone().unwrap(),
+            vec![(
+                overlay_file.as_file().try_clone().unwrap(),
+                0..512 * page_size as u64,
+            )],
+        );
+        assert_eq!(runtime.memory_files_size(), 1536 * page_size);
+
+        let progress = runtime.prefetch_backing_file();
+        wait_prefetched(
+            &progress,
+            runtime.memory_files_size(),
+            &[base_file.as_file(), overlay_file.as_file()],
+        );
+    }
+
+    #[test]
+    #[ignore = "benchmark, depends on the speed of the host"]
+    fn bench_prefetch_backing_file() {
+        if !can_evict_files() {
+            println!("Files can't be evicted from the page cache, skipping");
+            return;
+        }
+
+        // The guest runs for a while before touching most of its memory, which
+        // the prefetch overlaps with. Take the best of a few runs to smooth out
+        // the noise of the host.
+        let boot_time = Duration::from_millis(200);
+        let best_of = |prefetch| {
+            (0..3)
+                .map(|_| time_restore(1, prefetch, || (), boot_time))
+                .min()
+                .unwrap()
+        };
+        let cold = best_of(false);
+        let prefetched = best_of(true);
+        println!(
+            "Serving faults took {prefetched:?} with the memory file prefetched, {cold:?} without"
+        );
+        assert!(prefetched < cold);
+    }
+
+    /// Restores `num_pages` pages of guest memory from a base memory file filled
+    /// with 1s and the given overlays, each being the guest page numbers it
+    /// covers and the byte it's filled with. Returns the first byte of every page.
+    fn restore_with_overlays(num_pages: usize, overlays: &[(Range<u64>, u8)]) -> Vec<u8> {
+        let page_size = host_page_size();
+        let mem_size = num_pages * page_size;
+
+        let base_file = TempFile::new().unwrap();
+        base_file.as_file().write_all(&vec![1; mem_size]).unwrap();
+        let overlays = overlays
+            .iter()
+            .map(|(pages, fill)| {
+                let file = TempFile::new().unwrap();
+                let len = usize::try_from(pages.end - pages.start).unwrap() * page_size;
+                file.as_file().write_all(&vec![*fill; len]).unwrap();
+                let page = page_size as u64;
+                (
+                    file.as_file().try_clone().unwrap(),
+                    pages.start * page..pages.end * page,
+                )
+            })
+            .collect();
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let mut runtime =
+            Runtime::with_overlays(receiver, base_file.as_file().try_clone().unwrap(), overlays);
+        std::thread::spawn(move || {
+            runtime.run(|uffd_handler: &UffdHandler| {
+                if let Some(Event::Pagefault { .. }) = uffd_handler.read_event().unwrap() {
+                    // Serve the whole region at once, so that a single copy spans all files.
+                    let mapping = &uffd_handler.mem_regions[0].mapping;
+                    uffd_handler.serve_pf(mapping.base_host_virt_addr as *mut u8, mapping.size);
+                }
+            });
+        });
+
+        let (guest_memory, _uffd) = register_guest_memory(&sender, mem_size, FeatureFlags::empty());
+
+        let pages: Vec<u8> = (0..num_pages)
+            .map(|page| {
+                let addr = (guest_memory as usize + page * page_size) as *const u8;
+                // # Safety:
+                // The address is within the registered mapping.
+                unsafe { std::ptr::read_volatile(addr) }
+            })
+            .collect();
+
+        // # Safety:
+        // The mapping was created above and is no longer used.
+        unsafe { libc::munmap(guest_memory, mem_size) };
+        pages
+    }
+
+    #[test]
+    fn test_overlay() {
+        assert_eq!(restore_with_overlays(4, &[(1..2, 2)]), vec![1, 2, 1, 1]);
+    }
+
+    #[test]
+    fn test_overlapping_overlays() {
+        // The first overlay wins, wherever it starts.
+        assert_eq!(
+            restore_with_overlays(4, &[(1..2, 2), (0..4, 3)]),
+            vec![3, 2, 3, 3]
+        );
+        assert_eq!(
+            restore_with_overlays(4, &[(0..4, 3), (1..2, 2)]),
+            vec![3, 3, 3, 3]
+        );
+        assert_eq!(
+            restore_with_overlays(6, &[(2..3, 2), (1..4, 3)]),
+            vec![1, 3, 2, 3, 1, 1]
+        );
+    }
+
+    #[test]
+    fn test_fully_populated() {
+        let page_size = host_page_size();
+        let mappings = vec![
+            GuestRegionUffdMapping {
+                base_host_virt_addr: 0,
+                base_guest_phys_addr: 0,
+                size: 2 * page_size,
+                offset: 0,
+                page_size_kib: page_size,
+            },
+            GuestRegionUffdMapping {
+                base_host_virt_addr: 8 * page_size as u64,
+                base_guest_phys_addr: 8 * page_size as u64,
+                size: page_size,
+                offset: 2 * page_size as u64,
+                page_size_kib: page_size,
+            },
+        ];
+        let backing_memory = vec![0u8; 3 * page_size];
+        let handler = handler_from_mappings(&mappings, &backing_memory);
+        let page = page_size as u64;
+
+        assert_eq!(handler.total_pages(), 3);
+        assert_eq!(handler.resident_pages(), 0);
+
+        handler.update_mem_state_mappings(0, 2 * page, MemPageState::FromFile);
+        assert_eq!(handler.resident_pages(), 2);
+        assert!(!handler.take_fully_populated());
+
+        // Removing a page that was never populated doesn't change anything.
+        handler.update_mem_state_mappings(8 * page, 9 * page, MemPageState::Removed);
+        assert_eq!(handler.resident_pages(), 2);
+
+        handler.update_mem_state_mappings(8 * page, 9 * page, MemPageState::Anonymous);
+        assert_eq!(handler.resident_pages(), 3);
+        assert!(handler.take_fully_populated());
+        // It's only reported once.
+        assert!(!handler.take_fully_populated());
+
+        // Removed pages are not resident anymore.
+        handler.update_mem_state_mappings(0, page, MemPageState::Removed);
+        assert_eq!(handler.resident_pages(), 2);
+
+        // A range spanning both regions and the gap between them covers the pages of both.
+        handler.update_mem_state_mappings(page, 9 * page, MemPageState::Removed);
+        assert_eq!(handler.resident_pages(), 0);
+    }
+
+    #[test]
+    fn test_uds_listener_rebind() {
+        let tmp_dir = TempDir::new().unwrap();
+        let socket_path = tmp_dir.as_path().join("uffd.sock");
+
+        // The socket is removed when the previous handler goes away.
+        let listener = UdsListener::bind(&socket_path);
+        assert!(socket_path.exists());
+        drop(listener);
+        assert!(!socket_path.exists());
+        let listener = UdsListener::bind(&socket_path);
+
+        // A client can connect to the new handler.
+        let _stream = UnixStream::connect(&socket_path).unwrap();
+        listener.accept();
+        drop(listener);
+
+        // A stale socket and lock file left by a handler which didn't clean up
+        // are replaced.
+        drop(UnixListener::bind(&socket_path).unwrap());
+        File::create(tmp_dir.as_path().join("uffd.sock.lock")).unwrap();
+        let _listener = UdsListener::bind(&socket_path);
+        UnixStream::connect(&socket_path).unwrap();
+    }
+
+    #[test]
+    fn test_uds_listener_in_use() {
+        let tmp_dir = TempDir::new().unwrap();
+        let socket_path = tmp_dir.as_path().join("uffd.sock");
+
+        let live_listener = UdsListener::bind(&socket_path);
+        let err = std::panic::catch_unwind(|| UdsListener::bind(&socket_path)).unwrap_err();
+        assert!(err
+            .downcast_ref::<String>()
+            .unwrap()
+            .contains("is already in use"));
+
+        // The live handler is left untouched and gets the next connection.
+        let _stream = UnixStream::connect(&socket_path).unwrap();
+        live_listener.accept();
+        drop(live_listener);
+
+        // So is a socket something else listens on, even though it has no lock file.
+        let other_listener = UnixListener::bind(&socket_path).unwrap();
+        let err = std::panic::catch_unwind(|| UdsListener::bind(&socket_path)).unwrap_err();
+        assert!(err
+            .downcast_ref::<String>()
+            .unwrap()
+            .contains("is already in use"));
+        let _stream = UnixStream::connect(&socket_path).unwrap();
+        other_listener.accept().unwrap();
+    }
+
+    /// Environment variable through which `test_uds_listener_exit_on_signals` passes
+    /// the socket path to the handler process it spawns.
+    const HANDLER_SOCKET_ENV: &str = "UFFD_TEST_HANDLER_SOCKET";
+
+    /// Handler process spawned by `test_uds_listener_exit_on_signals`, which binds to
+    /// the socket and waits to be terminated, like the example handlers.
+    #[test]
+    #[ignore = "only runs as a process spawned by test_uds_listener_exit_on_signals"]
+    fn uds_listener_handler_process() {
+        let Some(socket_path) = std::env::var_os(HANDLER_SOCKET_ENV) else {
+            return;
+        };
+        let listener = UdsListener::bind(socket_path);
+        listener.exit_on_signals(|| println!("Handler exiting"));
+        println!("Handler ready");
+        loop {
+            listener.accept();
+        }
+    }
+
+    #[test]
+    fn test_uds_listener_exit_on_signals() {
+        let tmp_dir = TempDir::new().unwrap();
+        let socket_path = tmp_dir.as_path().join("uffd.sock");
+
+        // The test binary name doesn't include the crate name.
+        let (_, module) = module_path!().split_once("::").unwrap();
+        let mut handler = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                &format!("{module}::uds_listener_handler_process"),
+                "--ignored",
+                "--nocapture",
+            ])
+            .env(HANDLER_SOCKET_ENV, &socket_path)
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut output =
+            std::io::BufRead::lines(std::io::BufReader::new(handler.stdout.take().unwrap()));
+        // The harness prints the test name on the same line.
+        assert!(output.any(|line| line.unwrap().ends_with("Handler ready")));
+        assert!(socket_path.exists());
+
+        // # Safety:
+        // Sending a signal has no memory safety implications.
+        let ret =
+            unsafe { libc::kill(libc::pid_t::try_from(handler.id()).unwrap(), libc::SIGTERM) };
+        assert_eq!(ret, 0);
+        assert_eq!(handler.wait().unwrap().code(), Some(128 + libc::SIGTERM));
+        assert!(output.any(|line| line.unwrap() == "Handler exiting"));
+
+        // The handler cleaned up after itself.
+        assert!(!socket_path.exists());
+        assert!(!tmp_dir.as_path().join("uffd.sock.lock").exists());
+        let _listener = UdsListener::bind(&socket_path);
+    }
+
+    #[test]
+    fn test_fault_latency_summary() {
+        let mappings = vec![
+            GuestRegionUffdMapping {
+                base_host_virt_addr: 0x10000,
+                base_guest_phys_addr: 0,
+                size: 0x1000,
+                offset: 0,
+                page_size_kib: 0x1000,
+            },
+            GuestRegionUffdMapping {
+                base_host_virt_addr: 0x40000,
+                base_guest_phys_addr: 0x1_0000_0000,
+                size: 0x1000,
+                offset: 0x1000,
+                page_size_kib: 0x1000,
+            },
+        ];
+        let backing_memory = vec![0u8; 0x2000];
+        let handler = handler_from_mappings(&mappings, &backing_memory);
+
+        let first = &handler.mem_regions[0].fault_latency;
+        first.record(Duration::from_micros(5));
+        first.record(Duration::from_micros(9));
+        first.record(Duration::from_micros(10));
+        first.record(Duration::from_millis(5));
+        handler.mem_regions[1]
+            .fault_latency
+            .record(Duration::from_secs(1));
+
+        assert_eq!(first.counts(), vec![2, 1, 0, 1, 0]);
+        assert_eq!(
+            handler.mem_regions[1].fault_latency.counts(),
+            vec![0, 0, 0, 0, 1]
+        );
+        assert_eq!(
+            handler.fault_latency_summary(),
+            "Page fault latency per region:\n  guest address 0x0, 4096 bytes: <10us: 2 <100us: 1 \
+             <1ms: 0 <10ms: 1 >=10ms: 0\n  guest address 0x100000000, 4096 bytes: <10us: 0 \
+             <100us: 0 <1ms: 0 <10ms: 0 >=10ms: 1\n"
+        );
+    }
+
+    #[test]
+    fn test_handle_unexpected_event() {
+        let mappings = vec![GuestRegionUffdMapping {
+            base_host_virt_addr: 0x10000,
+            base_guest_phys_addr: 0,
+            size: 0x1000,
+            offset: 0,
+            page_size_kib: 0x1000,
+        }];
+        let backing_memory = vec![0u8; 0x1000];
+        let handler = handler_from_mappings(&mappings, &backing_memory);
+        assert!(handler.unhandled_events().is_empty());
+
+        // None of these bring the handler down.
+        handler.handle_unexpected_event(Event::Unmap {
+            start: ptr::null_mut(),
+            end: ptr::null_mut(),
+        });
+        handler.handle_unexpected_event(Event::Remap {
+            from: ptr::null_mut(),
+            to: ptr::null_mut(),
+            len: 0,
+        });
+        handler.handle_unexpected_event(Event::Unmap {
+            start: ptr::null_mut(),
+            end: ptr::null_mut(),
+        });
+
+        assert_eq!(
+            handler.unhandled_events(),
+            BTreeMap::from([("remap", 1), ("unmap", 2)])
+        );
+
+        // Neither do events of a type the kernel added after the `userfaultfd` crate.
+        handler.handle_read_error(Error::UnrecognizedEvent(0x17));
+        assert_eq!(
+            handler.unhandled_events(),
+            BTreeMap::from([("remap", 1), ("unknown", 1), ("unmap", 2)])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Failed to read uffd_msg")]
+    fn test_handle_read_error_fatal() {
+        let mappings = vec![GuestRegionUffdMapping {
+            base_host_virt_addr: 0x10000,
+            base_guest_phys_addr: 0,
+            size: 0x1000,
+            offset: 0,
+            page_size_kib: 0x1000,
+        }];
+        let backing_memory = vec![0u8; 0x1000];
+        let handler = handler_from_mappings(&mappings, &backing_memory);
+        handler.handle_read_error(Error::ReadEof);
+    }
+
+    #[test]
+    fn test_run_unexpected_event() {
+        let page_size = host_page_size();
+        let mem_size = 2 * page_size;
+        let memory_file = TempFile::new().unwrap();
+        memory_file.as_file().write_all(&vec![1; mem_size]).unwrap();
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let mut runtime = Runtime::new(receiver, memory_file.as_file().try_clone().unwrap());
+        let handlers = runtime.handlers();
+        std::thread::spawn(move || {
+            runtime.run(
+                |uffd_handler: &UffdHandler| match uffd_handler.read_event().unwrap() {
+                    Some(Event::Pagefault { addr, .. }) => {
+                        uffd_handler.serve_pf(addr.cast(), uffd_handler.page_size);
+                    }
+                    Some(event) => uffd_handler.handle_unexpected_event(event),
+                    None => {}
+                },
+            );
+        });
+
+        let (guest_memory, _uffd) =
+            register_guest_memory(&sender, mem_size, FeatureFlags::EVENT_UNMAP);
+        let read_page = |page: usize| {
+            let addr = (guest_memory as usize + page * page_size) as *const u8;
+            // # Safety:
+            // The address is within the registered mapping.
+            unsafe { std::ptr::read_volatile(addr) }
+        };
+        assert_eq!(read_page(0), 1);
+
+        // Unmapping the first page sends an `Unmap` event, which the loop doesn't
+        // handle. It has to keep serving the faults on the second page.
+        // # Safety:
+        // The range is within the mapping created above, and no longer used.
+        assert_eq!(unsafe { libc::munmap(guest_memory, page_size) }, 0);
+        assert_eq!(read_page(1), 1);
+        assert_eq!(
+            handlers.get()[0].unhandled_events(),
+            BTreeMap::from([("unmap", 1)])
+        );
+
+        // # Safety:
+        // The range is the rest of the mapping created above, and no longer used.
+        unsafe { libc::munmap(guest_memory.cast::<u8>().add(page_size).cast(), page_size) };
+    }
 }
@@ -1,52 +1,240 @@
 mod uffd_utils;
 
 use std::fs::File;
-use std::os::unix::net::UnixListener;
+use std::io::{Read, Write};
+use std::net::TcpListener;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use uffd_utils::{MemPageState, Runtime, UffdHandler};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
 
+use uffd_utils::{MemPageState, Runtime, UdsListener, UffdHandler, UffdHandlers};
+use userfaultfd::Event;
+
+/// Counters describing the work done by the handler.
+#[derive(Debug, Default)]
+struct Counters {
+    /// Page faults served, either from the memory file or with zeroes.
+    served: AtomicUsize,
+    /// Ranges removed through `UFFD_EVENT_REMOVE` (balloon inflation).
+    removed: AtomicUsize,
+    /// Page faults served with zeroes instead of memory file contents.
+    zeropage: AtomicUsize,
+}
+
+impl Counters {
+    /// Renders the counters in the Prometheus text exposition format.
+    fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, help, counter) in [
+            (
+                "uffd_pages_served_total",
+                "Page faults served.",
+                &self.served,
+            ),
+            (
+                "uffd_removed_total",
+                "Remove events received.",
+                &self.removed,
+            ),
+            (
+                "uffd_zeropages_total",
+                "Page faults served with zeroed pages.",
+                &self.zeropage,
+            ),
+        ] {
+            out.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} counter\n{name} {}\n",
+                counter.load(Ordering::SeqCst)
+            ));
+        }
+        out
+    }
+}
+
+/// How long a metrics client may take to send its request or read the reply. Connections are
+/// served one at a time, so a stalled client must not hold up the others for long.
+const METRICS_IO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Exports `counters` over HTTP on `addr`. Any request is answered with the
+/// current counter values, so the endpoint can be scraped at any path.
+fn spawn_metrics_server(addr: &str, counters: Arc<Counters>) {
+    let listener = TcpListener::bind(addr).expect("Cannot bind to metrics address");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            if stream.set_read_timeout(Some(METRICS_IO_TIMEOUT)).is_err()
+                || stream.set_write_timeout(Some(METRICS_IO_TIMEOUT)).is_err()
+            {
+                continue;
+            }
+            // The request contents don't matter, drain what was sent before replying.
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+
+            let body = counters.to_prometheus();
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: \
+                 {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+    });
+}
+
+fn handle_event(uffd_handler: &UffdHandler, event: Event, counters: &Counters) {
+    // We expect to receive either a Page Fault or Removed
+    // event (if the balloon device is enabled).
+    match event {
+        Event::Pagefault { addr, .. } => {
+            let state = uffd_handler.serve_pf(addr.cast(), uffd_handler.page_size);
+            // Increment the page fault counters
+            counters.served.fetch_add(1, Ordering::SeqCst);
+            if let MemPageState::Anonymous = state {
+                counters.zeropage.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        Event::Remove { start, end } => {
+            uffd_handler.update_mem_state_mappings(start as u64, end as u64, MemPageState::Removed);
+            counters.removed.fetch_add(1, Ordering::SeqCst);
+        }
+        event => uffd_handler.handle_unexpected_event(event),
+    }
+
+    // Print the current count of pages served
+    println!("Pages served: {}", counters.served.load(Ordering::SeqCst));
+    if uffd_handler.take_fully_populated() {
+        println!("Guest memory fully populated");
+    }
+}
+
+/// Prints what the handler did, once it gets terminated.
+fn print_exit_summary(counters: &Counters, handlers: Option<&UffdHandlers>) {
+    println!("Pages served: {}", counters.served.load(Ordering::SeqCst));
+    for uffd_handler in handlers.map(UffdHandlers::get).unwrap_or_default() {
+        print!("{}", uffd_handler.fault_latency_summary());
+        println!("Unhandled events: {:?}", uffd_handler.unhandled_events());
+    }
+}
+
+/// Prints the memory file prefetch progress every second until it's done.
+fn spawn_prefetch_reporter(progress: Arc<AtomicUsize>, total: usize) {
+    thread::spawn(move || loop {
+        let done = progress.load(Ordering::SeqCst);
+        println!("Memory file prefetched: {done}/{total} bytes");
+        if done == total {
+            break;
+        }
+        thread::sleep(Duration::from_secs(1));
+    });
+}
+
+/// Usage: `uffd_valid_count_handler [--metrics-addr <addr>] [--workers <n>] [--prefetch]
+/// <uffd_sock_path> <mem_file_path>`
+///
+/// `--workers` sets the number of threads serving page faults. By default faults are
+/// served from the main thread, more workers can speed up the restore of guests with
+/// many vCPUs faulting concurrently.
+///
+/// `--prefetch` reads the memory file into the page cache in the background, so that
+/// faults on a cold memory file don't have to wait for the disk.
 fn main() {
-    let mut args = std::env::args();
-    let uffd_sock_path = args.nth(1).expect("No socket path given");
-    let mem_file_path = args.next().expect("No memory file given");
+    let mut metrics_addr = None;
+    let mut workers = 1;
+    let mut prefetch = false;
+    let mut positional_args = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--metrics-addr" => {
+                metrics_addr = Some(args.next().expect("No address given for --metrics-addr"));
+            }
+            "--workers" => {
+                workers = args
+                    .next()
+                    .and_then(|workers| workers.parse::<usize>().ok())
+                    .filter(|workers| *workers > 0)
+                    .expect("--workers expects a positive number");
+            }
+            "--prefetch" => prefetch = true,
+            _ => positional_args.push(arg),
+        }
+    }
+    let mut positional_args = positional_args.into_iter();
+    let uffd_sock_path = positional_args.next().expect("No socket path given");
+    let mem_file_path = positional_args.next().expect("No memory file given");
 
     let file = File::open(mem_file_path).expect("Cannot open memfile");
 
+    // Introduce counters for page faults and remove events
+    let counters = Arc::new(Counters::default());
+    // Only known once Firecracker connects.
+    let handlers = Arc::new(OnceLock::new());
+
+    // Print the summary however the handler exits.
+    let listener = UdsListener::bind(uffd_sock_path);
+    listener.exit_on_signals({
+        let counters = Arc::clone(&counters);
+        let handlers = Arc::clone(&handlers);
+        move || print_exit_summary(&counters, handlers.get())
+    });
+
+    if let Some(metrics_addr) = metrics_addr {
+        spawn_metrics_server(&metrics_addr, Arc::clone(&counters));
+    }
+
     // Get Uffd from UDS. We'll use the uffd to handle PFs for Firecracker.
-    let listener = UnixListener::bind(uffd_sock_path).expect("Cannot bind to socket path");
-    let (stream, _) = listener.accept().expect("Cannot listen on UDS socket");
+    let stream = listener.accept();
 
     let mut runtime = Runtime::new(stream, file);
+    handlers
+        .set(runtime.handlers())
+        .expect("Handlers already set");
+    if prefetch {
+        spawn_prefetch_reporter(runtime.prefetch_backing_file(), runtime.memory_files_size());
+    }
 
-    // Introduce a counter for page faults
-    let page_fault_counter = Arc::new(AtomicUsize::new(0));
-    let page_fault_counter_clone = Arc::clone(&page_fault_counter);
-
-    runtime.run(move |uffd_handler: &mut UffdHandler| {
-        // Read an event from the userfaultfd.
-        let event = uffd_handler
-            .read_event()
-            .expect("Failed to read uffd_msg")
-            .expect("uffd_msg not ready");
-
-        // We expect to receive either a Page Fault or Removed
-        // event (if the balloon device is enabled).
-        match event {
-            userfaultfd::Event::Pagefault { addr, .. } => {
-                uffd_handler.serve_pf(addr.cast(), uffd_handler.page_size);
-                // Increment the page fault counter
-                page_fault_counter_clone.fetch_add(1, Ordering::SeqCst);
-            }
-            userfaultfd::Event::Remove { start, end } => uffd_handler.update_mem_state_mappings(
-                start as u64,
-                end as u64,
-                MemPageState::Removed,
-            ),
-            _ => panic!("Unexpected event on userfaultfd"),
-        }
-        
-        // Print the current count of pages served
-        println!("Pages served: {}", page_fault_counter_clone.load(Ordering::SeqCst));
-    });
+    if workers > 1 {
+        runtime.run_parallel(workers, |uffd_handler: &UffdHandler, event: Event| {
+            handle_event(uffd_handler, event, &counters)
+        });
+    } else {
+        runtime.run(|uffd_handler: &UffdHandler| {
+            // Read an event from the userfaultfd.
+            let event = match uffd_handler.read_event() {
+                Ok(event) => event.expect("uffd_msg not ready"),
+                Err(err) => {
+                    uffd_handler.handle_read_error(err);
+                    return;
+                }
+            };
+            handle_event(uffd_handler, event, &counters)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_prometheus() {
+        let counters = Counters::default();
+        counters.served.store(7, Ordering::SeqCst);
+        counters.removed.store(2, Ordering::SeqCst);
+        counters.zeropage.store(3, Ordering::SeqCst);
+
+        assert_eq!(
+            counters.to_prometheus(),
+            "# HELP uffd_pages_served_total Page faults served.\n# TYPE uffd_pages_served_total \
+             counter\nuffd_pages_served_total 7\n# HELP uffd_removed_total Remove events \
+             received.\n# TYPE uffd_removed_total counter\nuffd_removed_total 2\n# HELP \
+             uffd_zeropages_total Page faults served with zeroed pages.\n# TYPE \
+             uffd_zeropages_total counter\nuffd_zeropages_total 3\n"
+        );
+    }
 }
@@ -8,9 +8,8 @@
 mod uffd_utils;
 
 use std::fs::File;
-use std::os::unix::net::UnixListener;
 
-use uffd_utils::{MemPageState, Runtime, UffdHandler};
+use uffd_utils::{MemPageState, Runtime, UdsListener, UffdHandler};
 
 fn main() {
     let mut args = std::env::args();
@@ -20,29 +19,33 @@ fn main() {
     let file = File::open(mem_file_path).expect("Cannot open memfile");
 
     // Get Uffd from UDS. We'll use the uffd to handle PFs for Firecracker.
-    let listener = UnixListener::bind(uffd_sock_path).expect("Cannot bind to socket path");
-    let (stream, _) = listener.accept().expect("Cannot listen on UDS socket");
+    let listener = UdsListener::bind(uffd_sock_path);
+    listener.exit_on_signals(|| ());
+    let stream = listener.accept();
 
     let mut runtime = Runtime::new(stream, file);
-    runtime.run(|uffd_handler: &mut UffdHandler| {
+    runtime.run(|uffd_handler: &UffdHandler| {
         // Read an event from the userfaultfd.
-        let event = uffd_handler
-            .read_event()
-            .expect("Failed to read uffd_msg")
-            .expect("uffd_msg not ready");
+        let event = match uffd_handler.read_event() {
+            Ok(event) => event.expect("uffd_msg not ready"),
+            Err(err) => {
+                uffd_handler.handle_read_error(err);
+                return;
+            }
+        };
 
         // We expect to receive either a Page Fault or Removed
         // event (if the balloon device is enabled).
         match event {
             userfaultfd::Event::Pagefault { addr, .. } => {
-                uffd_handler.serve_pf(addr.cast(), uffd_handler.page_size)
+                uffd_handler.serve_pf(addr.cast(), uffd_handler.page_size);
             }
             userfaultfd::Event::Remove { start, end } => uffd_handler.update_mem_state_mappings(
                 start as u64,
                 end as u64,
                 MemPageState::Removed,
             ),
-            _ => panic!("Unexpected event on userfaultfd"),
+            event => uffd_handler.handle_unexpected_event(event),
         }
     });
 }
@@ -95,11 +95,17 @@ pub struct MicrovmState {
 /// E.g. Guest memory contents for a region of `size` bytes can be found in the
 /// backend at `offset` bytes from the beginning, and should be copied/populated
 /// into `base_host_address`.
+///
+/// There is no per-region balloon information: every region is guest RAM the
+/// balloon device can reclaim pages from, and the pages it actually reclaims
+/// are reported to the handler through `UFFD_EVENT_REMOVE` events.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct GuestRegionUffdMapping {
     /// Base host virtual address where the guest memory contents for this
     /// region should be copied/populated.
     pub base_host_virt_addr: u64,
+    /// Base guest physical address of this region.
+    pub base_guest_phys_addr: u64,
     /// Region size.
     pub size: usize,
     /// Offset in the backend file/buffer where the region contents are.
@@ -584,6 +590,7 @@ fn create_guest_memory(
     for (mem_region, state_region) in guest_memory.iter().zip(mem_state.regions.iter()) {
         backend_mappings.push(GuestRegionUffdMapping {
             base_host_virt_addr: mem_region.as_ptr() as u64,
+            base_guest_phys_addr: state_region.base_address,
             size: mem_region.size(),
             offset: state_region.offset,
             page_size_kib: huge_pages.page_size_kib(),
@@ -764,7 +771,7 @@ mod tests {
     fn test_create_guest_memory() {
         let mem_state = GuestMemoryState {
             regions: vec![GuestMemoryRegionState {
-                base_address: 0,
+                base_address: 0x100000,
                 size: 0x20000,
                 offset: 0x10000,
             }],
@@ -774,6 +781,7 @@ mod tests {
             create_guest_memory(&mem_state, false, HugePageConfig::None).unwrap();
 
         assert_eq!(uffd_regions.len(), 1);
+        assert_eq!(uffd_regions[0].base_guest_phys_addr, 0x100000);
         assert_eq!(uffd_regions[0].size, 0x20000);
         assert_eq!(uffd_regions[0].offset, 0x10000);
         assert_eq!(
@@ -787,12 +795,14 @@ mod tests {
         let uffd_regions = vec![
             GuestRegionUffdMapping {
                 base_host_virt_addr: 0,
+                base_guest_phys_addr: 0,
                 size: 0x100000,
                 offset: 0,
                 page_size_kib: HugePageConfig::None.page_size_kib(),
             },
             GuestRegionUffdMapping {
                 base_host_virt_addr: 0x100000,
+                base_guest_phys_addr: 0x100000,
                 size: 0x200000,
                 offset: 0,
                 page_size_kib: HugePageConfig::Hugetlbfs2M.page_size_kib(),